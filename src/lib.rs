@@ -4,11 +4,27 @@
 //! Read the documentation on the underlying syslog5424 crate to see
 //! the specifics on the formatting: []()
 //!
-//! Performance was not the main goal with this crate, so it may be
-//! a bit slower than some other implementations:
-//! * The buffer is not reused between messages
-//! * When verifying/converting the message according to RFC5424, 3 String allocations
-//! take place
+//! The formatted message is built into a thread-local buffer that is
+//! reused between calls, so each `log` call does one `write_all` instead
+//! of allocating a fresh buffer and writing piecemeal.
+//!
+//! That does not make the path allocation-free, and it can't: the
+//! underlying `syslog5424::Rfc5424Data::structured_data` contract hands
+//! the formatter ownership of a `HashMap<&str, Vec<(String, String)>>`,
+//! which it consumes and drops before control returns here, so there is
+//! no hook to pool or reclaim that `Vec`/`HashMap` across records. What we
+//! *can* control is how many of the `String`s going into it are freshly
+//! allocated versus borrowed: `sanitize_name`/`escape_value` return
+//! `Cow<str>` and only copy when a key or value actually contains bytes
+//! that need sanitizing or escaping, and slog keys (already `&'static
+//! str`) and `emit_str` values are routed through as borrows instead of
+//! being `.to_string()`'d up front. Bools are routed through as static
+//! `"true"`/`"false"` borrows and chars through a stack buffer, but the
+//! numeric `emit_*` overrides still allocate one `String` per value,
+//! since there's no string to borrow from a `u64` or `f64` in the first
+//! place, and the
+//! final insertion into the `Vec<(String, String)>` always allocates once
+//! per key and per value, since `StructuredData` requires owned `String`s.
 //!
 //! `slog-async` should probably almost always be used with this crate.
 
@@ -18,6 +34,8 @@ extern crate chrono;
 extern crate slog;
 extern crate syslog5424;
 
+pub mod transport;
+
 // re-exports
 pub use syslog5424::iana::{Origin, TimeQuality};
 pub use syslog5424::types::Facility;
@@ -28,26 +46,185 @@ use slog::{Drain, Level, OwnedKVList, Record, Serializer, KV};
 use syslog5424::types::{Message, Severity};
 use syslog5424::{Rfc5424Data, StructuredData};
 
-use std::cell::RefCell;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use std::fmt::Arguments;
+use std::fmt::{self, Arguments};
 use std::io::{self, Write};
 
+thread_local! {
+    // Scratch space for the formatted RFC5424 message. Cleared and reused
+    // on every `log` call instead of allocating a fresh `Vec` each time.
+    static FORMAT_BUF: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+    // Remembers how many distinct SD-ID groups the last record on this
+    // thread was routed into, so the next `StructuredWrapper` can reserve
+    // its `StructuredData` map up front rather than growing it entry by
+    // entry.
+    static GROUP_CAPACITY_HINT: Cell<usize> = Cell::new(1);
+}
+
+/// Routing policy deciding which structured-data SD-ID an incoming slog
+/// key is grouped under.
+///
+/// The enterprise-number suffix (e.g. the `@32473` in `db@32473`) is
+/// appended by the underlying `Rfc5424` formatter, so routers only need
+/// to return the bare SD-ID name.
+pub enum SdIdRouter {
+    /// Route every key into the same SD-ID.
+    Fixed(&'static str),
+    /// Route by the longest matching prefix, falling back to `default`
+    /// if no prefix matches.
+    Prefixes {
+        /// `(prefix, sd_id)` pairs, checked longest-prefix-first.
+        table: Vec<(&'static str, &'static str)>,
+        /// SD-ID used for keys that match no prefix.
+        default: &'static str,
+    },
+    /// Route using an arbitrary closure.
+    Custom(Box<dyn Fn(&str) -> &'static str + Send + Sync>),
+}
+
+impl SdIdRouter {
+    /// Route every key into the same SD-ID.
+    pub fn fixed(sd_id: &'static str) -> SdIdRouter {
+        SdIdRouter::Fixed(sd_id)
+    }
+
+    /// Route by the longest matching prefix, falling back to `default`
+    /// if no prefix matches.
+    pub fn from_prefixes(table: Vec<(&'static str, &'static str)>, default: &'static str) -> SdIdRouter {
+        SdIdRouter::Prefixes { table, default }
+    }
+
+    /// Route using an arbitrary closure.
+    pub fn from_fn<F>(f: F) -> SdIdRouter
+    where
+        F: Fn(&str) -> &'static str + Send + Sync + 'static,
+    {
+        SdIdRouter::Custom(Box::new(f))
+    }
+
+    fn route(&self, key: &str) -> &'static str {
+        match self {
+            SdIdRouter::Fixed(sd_id) => sd_id,
+            SdIdRouter::Prefixes { table, default } => table
+                .iter()
+                .filter(|(prefix, _)| key.starts_with(prefix))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, sd_id)| *sd_id)
+                .unwrap_or(default),
+            SdIdRouter::Custom(f) => f(key),
+        }
+    }
+}
+
+impl Default for SdIdRouter {
+    /// Routes every key into the single `"slog"` SD-ID, matching the
+    /// drain's historical behavior.
+    fn default() -> SdIdRouter {
+        SdIdRouter::fixed("slog")
+    }
+}
+
+impl fmt::Debug for SdIdRouter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SdIdRouter::Fixed(sd_id) => f.debug_tuple("Fixed").field(sd_id).finish(),
+            SdIdRouter::Prefixes { table, default } => {
+                f.debug_struct("Prefixes").field("table", table).field("default", default).finish()
+            }
+            SdIdRouter::Custom(_) => f.debug_tuple("Custom").field(&"<fn>").finish(),
+        }
+    }
+}
+
+/// Maximum length of an RFC5424 MSGID.
+const MAX_MSGID_LEN: usize = 32;
+
+/// Keep only the printable US-ASCII bytes of `raw` and truncate to the
+/// RFC5424 MSGID length limit, dropping anything that doesn't fit.
+fn sanitize_msgid(raw: &str) -> Option<String> {
+    let filtered: String = raw.bytes().filter(|b| (0x21..=0x7e).contains(b)).map(|b| b as char).collect();
+    if filtered.is_empty() {
+        None
+    } else {
+        let mut filtered = filtered;
+        filtered.truncate(MAX_MSGID_LEN);
+        Some(filtered)
+    }
+}
+
+/// Extracts the RFC5424 MSGID for a record.
+///
+/// Defaults to the record's slog tag via [`MsgIdExtractor::default`].
+pub struct MsgIdExtractor(Box<dyn Fn(&Record) -> Option<String> + Send + Sync>);
+
+impl MsgIdExtractor {
+    /// Derive the MSGID from an arbitrary closure, e.g. one reading a
+    /// key-value pair or the record's module path.
+    pub fn from_fn<F>(f: F) -> MsgIdExtractor
+    where
+        F: Fn(&Record) -> Option<String> + Send + Sync + 'static,
+    {
+        MsgIdExtractor(Box::new(f))
+    }
+
+    fn extract(&self, record: &Record) -> Option<String> {
+        (self.0)(record).and_then(|msgid| sanitize_msgid(&msgid))
+    }
+}
+
+impl Default for MsgIdExtractor {
+    /// Derives the MSGID from the record's slog tag.
+    fn default() -> MsgIdExtractor {
+        MsgIdExtractor::from_fn(|record| Some(record.tag().to_string()))
+    }
+}
+
+impl fmt::Debug for MsgIdExtractor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MsgIdExtractor").field(&"<fn>").finish()
+    }
+}
+
 /// Rfc5424 `slog` writer
 #[derive(Debug)]
 pub struct Rfc5424Writer<W: Write> {
     writer: RefCell<W>,
     formatter: Rfc5424,
+    sd_id_router: SdIdRouter,
+    msgid_extractor: MsgIdExtractor,
 }
 
 impl<W: Write> Rfc5424Writer<W> {
-    /// Create a new `Rfc5424Writer` which implements `slog::Drain`
+    /// Create a new `Rfc5424Writer` which implements `slog::Drain`.
+    ///
+    /// All keys are routed into a single `"slog"` structured-data
+    /// element; use [`with_sd_id_router`](Rfc5424Writer::with_sd_id_router)
+    /// to change that. The MSGID defaults to the record's slog tag; use
+    /// [`with_msgid_extractor`](Rfc5424Writer::with_msgid_extractor) to
+    /// change that.
     pub fn new(writer: W, formatter: Rfc5424) -> Rfc5424Writer<W> {
         Rfc5424Writer {
             writer: RefCell::new(writer),
             formatter,
+            sd_id_router: SdIdRouter::default(),
+            msgid_extractor: MsgIdExtractor::default(),
         }
     }
+
+    /// Set the policy used to route slog keys into structured-data
+    /// SD-IDs.
+    pub fn with_sd_id_router(mut self, sd_id_router: SdIdRouter) -> Rfc5424Writer<W> {
+        self.sd_id_router = sd_id_router;
+        self
+    }
+
+    /// Set the extractor used to derive the RFC5424 MSGID from a record.
+    pub fn with_msgid_extractor(mut self, msgid_extractor: MsgIdExtractor) -> Rfc5424Writer<W> {
+        self.msgid_extractor = msgid_extractor;
+        self
+    }
 }
 
 /// Wrapper struct to store all the information provied by `slog`
@@ -56,16 +233,192 @@ impl<W: Write> Rfc5424Writer<W> {
 struct CompleteLogEntry<'a> {
     record: &'a Record<'a>,
     values: &'a OwnedKVList,
+    sd_id_router: &'a SdIdRouter,
+    msgid_extractor: &'a MsgIdExtractor,
+}
+
+/// Maximum length of an RFC5424 SD-NAME/PARAM-NAME.
+const MAX_SD_NAME_LEN: usize = 32;
+
+/// Sanitize a slog key into a valid RFC5424 SD-NAME/PARAM-NAME: printable
+/// US-ASCII excluding space, `=`, `]` and `"`, truncated to 32 characters.
+/// Any disallowed byte is replaced with `_`.
+///
+/// Borrows `name` unchanged when it's already a valid SD-NAME, which is
+/// the common case for slog keys, so no allocation happens on that path.
+fn sanitize_name(name: &str) -> Cow<str> {
+    if name.len() <= MAX_SD_NAME_LEN && name.bytes().all(is_valid_name_byte) {
+        return Cow::Borrowed(name);
+    }
+
+    let mut sanitized: String = name
+        .bytes()
+        .map(|b| if is_valid_name_byte(b) { b as char } else { '_' })
+        .collect();
+    sanitized.truncate(MAX_SD_NAME_LEN);
+    Cow::Owned(sanitized)
+}
+
+fn is_valid_name_byte(b: u8) -> bool {
+    (0x21..=0x7e).contains(&b) && b != b'=' && b != b']' && b != b'"'
+}
+
+/// Escape a value for use as an RFC5424 PARAM-VALUE: `"`, `\` and `]`
+/// must be backslash-escaped.
+///
+/// Borrows `value` unchanged when it contains nothing that needs
+/// escaping, which is the common case, so no allocation happens on that
+/// path.
+fn escape_value(value: &str) -> Cow<str> {
+    if !value.contains(|c| c == '"' || c == '\\' || c == ']') {
+        return Cow::Borrowed(value);
+    }
+
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '"' || c == '\\' || c == ']' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    Cow::Owned(escaped)
 }
 
-/// Wrapper for a vec so that we can implement `Serializer` on it.
-struct StructuredWrapper(Vec<(String, String)>);
+/// Make `name` unique among the names already present in `entries` by
+/// suffixing an index, re-truncating so the result still fits within
+/// `MAX_SD_NAME_LEN`.
+fn dedupe_name<'a>(entries: &[(String, String)], name: Cow<'a, str>) -> Cow<'a, str> {
+    if !entries.iter().any(|(existing, _)| existing.as_str() == name.as_ref()) {
+        return name;
+    }
 
-/// The most basic serializer. Convert `key` and `val` to strings
-/// and store them as pairs in a vec.
-impl<'a> Serializer for StructuredWrapper {
+    let mut index = 2;
+    loop {
+        let suffix = format!("_{}", index);
+        let mut candidate = name.clone().into_owned();
+        candidate.truncate(MAX_SD_NAME_LEN.saturating_sub(suffix.len()));
+        candidate.push_str(&suffix);
+        if !entries.iter().any(|(existing, _)| *existing == candidate) {
+            return Cow::Owned(candidate);
+        }
+        index += 1;
+    }
+}
+
+/// Serializer that routes each key/value pair into its configured
+/// structured-data group, sanitizing names and escaping values along the
+/// way so the emitted structured data is always valid RFC5424.
+///
+/// The common value types each get their own `emit_*` override so they
+/// are converted directly (strings, bools and chars without `ToString`,
+/// numbers with `ToString`) instead of going through
+/// `format!("{}", val)`, which is the path `emit_arguments` has to take
+/// since it only has an opaque `Arguments` to work with.
+struct StructuredWrapper<'a> {
+    router: &'a SdIdRouter,
+    groups: StructuredData,
+}
+
+impl<'a> StructuredWrapper<'a> {
+    fn new(router: &'a SdIdRouter, group_capacity: usize) -> StructuredWrapper<'a> {
+        StructuredWrapper {
+            router,
+            groups: HashMap::with_capacity(group_capacity),
+        }
+    }
+
+    /// Route, sanitize and store a key/value pair. Takes borrowed `&str`s
+    /// rather than owned `String`s so that callers already holding a
+    /// borrow (slog keys are `&'static str`; `emit_str` values are
+    /// already `&str`) don't pay for an allocation that `sanitize_name`/
+    /// `escape_value` may end up discarding anyway.
+    fn push(&mut self, key: &str, val: &str) {
+        let sd_id = self.router.route(key);
+        let entry = self.groups.entry(sd_id).or_default();
+        let name = dedupe_name(entry, sanitize_name(key));
+        entry.push((name.into_owned(), escape_value(val).into_owned()));
+    }
+}
+
+impl<'a> Serializer for StructuredWrapper<'a> {
     fn emit_arguments(&mut self, key: slog::Key, val: &Arguments) -> slog::Result {
-        self.0.push((key.to_string(), format!("{}", val)));
+        self.push(key, &format!("{}", val));
+        Ok(())
+    }
+
+    fn emit_str(&mut self, key: slog::Key, val: &str) -> slog::Result {
+        self.push(key, val);
+        Ok(())
+    }
+
+    fn emit_bool(&mut self, key: slog::Key, val: bool) -> slog::Result {
+        self.push(key, if val { "true" } else { "false" });
+        Ok(())
+    }
+
+    fn emit_char(&mut self, key: slog::Key, val: char) -> slog::Result {
+        let mut buf = [0u8; 4];
+        self.push(key, val.encode_utf8(&mut buf));
+        Ok(())
+    }
+
+    fn emit_u8(&mut self, key: slog::Key, val: u8) -> slog::Result {
+        self.push(key, &val.to_string());
+        Ok(())
+    }
+
+    fn emit_i8(&mut self, key: slog::Key, val: i8) -> slog::Result {
+        self.push(key, &val.to_string());
+        Ok(())
+    }
+
+    fn emit_u16(&mut self, key: slog::Key, val: u16) -> slog::Result {
+        self.push(key, &val.to_string());
+        Ok(())
+    }
+
+    fn emit_i16(&mut self, key: slog::Key, val: i16) -> slog::Result {
+        self.push(key, &val.to_string());
+        Ok(())
+    }
+
+    fn emit_u32(&mut self, key: slog::Key, val: u32) -> slog::Result {
+        self.push(key, &val.to_string());
+        Ok(())
+    }
+
+    fn emit_i32(&mut self, key: slog::Key, val: i32) -> slog::Result {
+        self.push(key, &val.to_string());
+        Ok(())
+    }
+
+    fn emit_u64(&mut self, key: slog::Key, val: u64) -> slog::Result {
+        self.push(key, &val.to_string());
+        Ok(())
+    }
+
+    fn emit_i64(&mut self, key: slog::Key, val: i64) -> slog::Result {
+        self.push(key, &val.to_string());
+        Ok(())
+    }
+
+    fn emit_usize(&mut self, key: slog::Key, val: usize) -> slog::Result {
+        self.push(key, &val.to_string());
+        Ok(())
+    }
+
+    fn emit_isize(&mut self, key: slog::Key, val: isize) -> slog::Result {
+        self.push(key, &val.to_string());
+        Ok(())
+    }
+
+    fn emit_f32(&mut self, key: slog::Key, val: f32) -> slog::Result {
+        self.push(key, &val.to_string());
+        Ok(())
+    }
+
+    fn emit_f64(&mut self, key: slog::Key, val: f64) -> slog::Result {
+        self.push(key, &val.to_string());
         Ok(())
     }
 }
@@ -89,19 +442,23 @@ impl<'a> Rfc5424Data for CompleteLogEntry<'a> {
     }
 
     fn structured_data(&self) -> Option<StructuredData> {
-        let mut data: StructuredData = HashMap::new();
-        let mut buf = StructuredWrapper(Vec::new());
+        let cap = GROUP_CAPACITY_HINT.with(Cell::get);
+        let mut buf = StructuredWrapper::new(self.sd_id_router, cap);
         // our serializer never errors (only writes to a vec)
         self.record.kv().serialize(self.record, &mut buf).unwrap();
         self.values.serialize(self.record, &mut buf).unwrap();
 
-        data.insert("slog", buf.0);
-        Some(data)
+        GROUP_CAPACITY_HINT.with(|hint| hint.set(buf.groups.len().max(1)));
+        Some(buf.groups)
     }
 
     fn message(&self) -> Option<Message> {
         Some(Message::Text(format!("{}", self.record.msg())))
     }
+
+    fn msgid(&self) -> Option<String> {
+        self.msgid_extractor.extract(self.record)
+    }
 }
 
 impl<W: Write> Drain for Rfc5424Writer<W> {
@@ -109,8 +466,162 @@ impl<W: Write> Drain for Rfc5424Writer<W> {
     type Err = io::Error;
 
     fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
-        let msg = CompleteLogEntry { record, values };
+        let msg = CompleteLogEntry {
+            record,
+            values,
+            sd_id_router: &self.sd_id_router,
+            msgid_extractor: &self.msgid_extractor,
+        };
         let mut writer = self.writer.borrow_mut();
-        self.formatter.format(&mut *writer, &msg)
+        FORMAT_BUF.with(|buf| {
+            let mut buf = buf.borrow_mut();
+            buf.clear();
+            self.formatter.format(&mut *buf, &msg)?;
+            writer.write_all(&buf)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sd_id_router_fixed_routes_every_key_to_the_same_id() {
+        let router = SdIdRouter::fixed("slog");
+        assert_eq!(router.route("db.latency"), "slog");
+        assert_eq!(router.route("anything.else"), "slog");
+    }
+
+    #[test]
+    fn sd_id_router_prefixes_picks_the_longest_matching_prefix() {
+        let router = SdIdRouter::from_prefixes(
+            vec![("db", "db"), ("db.latency", "db_latency"), ("http", "http")],
+            "default",
+        );
+        assert_eq!(router.route("db.latency.read"), "db_latency");
+        assert_eq!(router.route("db.pool_size"), "db");
+        assert_eq!(router.route("http.status"), "http");
+    }
+
+    #[test]
+    fn sd_id_router_prefixes_falls_back_to_default_when_nothing_matches() {
+        let router = SdIdRouter::from_prefixes(vec![("db", "db")], "default");
+        assert_eq!(router.route("http.status"), "default");
+    }
+
+    #[test]
+    fn sd_id_router_custom_uses_the_given_closure() {
+        let router = SdIdRouter::from_fn(|key| if key.starts_with("db") { "db" } else { "other" });
+        assert_eq!(router.route("db.latency"), "db");
+        assert_eq!(router.route("http.status"), "other");
+    }
+
+    fn is_valid_sd_name(name: &str) -> bool {
+        !name.is_empty() && name.len() <= MAX_SD_NAME_LEN && name.bytes().all(is_valid_name_byte)
+    }
+
+    fn is_valid_param_value(value: &str) -> bool {
+        let bytes = value.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'"' | b']' => return false,
+                b'\\' => {
+                    i += 1;
+                    if i >= bytes.len() {
+                        return false;
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        true
+    }
+
+    #[test]
+    fn sanitize_name_replaces_forbidden_bytes_and_truncates() {
+        let name = sanitize_name("db latency=\"total\"] and then some more padding to overflow");
+        assert!(is_valid_sd_name(&name));
+        assert_eq!(name.as_ref(), "db_latency__total___and_then_som");
+    }
+
+    #[test]
+    fn sanitize_name_is_idempotent_for_already_valid_names() {
+        let name = sanitize_name("http.status");
+        assert_eq!(name.as_ref(), "http.status");
+        assert!(is_valid_sd_name(&name));
+    }
+
+    #[test]
+    fn sanitize_name_borrows_instead_of_allocating_when_already_valid() {
+        match sanitize_name("http.status") {
+            Cow::Borrowed(_) => {}
+            Cow::Owned(_) => panic!("expected an already-valid name to be borrowed, not copied"),
+        }
+    }
+
+    #[test]
+    fn escape_value_backslash_escapes_forbidden_bytes() {
+        let escaped = escape_value(r#"path="C:\tmp" [brackets]"#);
+        assert!(is_valid_param_value(&escaped));
+        assert_eq!(escaped.as_ref(), r#"path=\"C:\\tmp\" [brackets\]"#);
+    }
+
+    #[test]
+    fn escape_value_borrows_instead_of_allocating_when_nothing_needs_escaping() {
+        match escape_value("nothing to escape here") {
+            Cow::Borrowed(_) => {}
+            Cow::Owned(_) => panic!("expected a plain value to be borrowed, not copied"),
+        }
+    }
+
+    #[test]
+    fn dedupe_name_suffixes_colliding_names() {
+        let entries = vec![("key".to_string(), "1".to_string())];
+        let deduped = dedupe_name(&entries, Cow::Borrowed("key"));
+        assert_eq!(deduped.as_ref(), "key_2");
+        assert!(is_valid_sd_name(&deduped));
+    }
+
+    #[test]
+    fn dedupe_name_truncates_long_names_so_the_suffix_still_fits() {
+        let long_name = "a".repeat(MAX_SD_NAME_LEN);
+        let entries = vec![(long_name.clone(), "1".to_string())];
+        let deduped = dedupe_name(&entries, Cow::Owned(long_name));
+        assert!(is_valid_sd_name(&deduped));
+        assert!(deduped.ends_with("_2"));
+    }
+
+    #[test]
+    fn structured_wrapper_sanitizes_and_dedupes_pathological_keys() {
+        let router = SdIdRouter::default();
+        let mut buf = StructuredWrapper::new(&router, 1);
+        buf.push("weird key]", "value\"with\\special]chars");
+        buf.push("weird key]", "second");
+
+        let entries = &buf.groups["slog"];
+        assert_eq!(entries.len(), 2);
+        for (name, value) in entries {
+            assert!(is_valid_sd_name(name), "{} is not a valid SD-NAME", name);
+            assert!(is_valid_param_value(value), "{} is not a valid PARAM-VALUE", value);
+        }
+        assert_ne!(entries[0].0, entries[1].0);
+    }
+
+    #[test]
+    fn sanitize_msgid_drops_non_printable_bytes_and_truncates() {
+        let msgid = sanitize_msgid("db query\u{0}").unwrap();
+        assert_eq!(msgid, "dbquery");
+
+        let long = sanitize_msgid(&"x".repeat(MAX_MSGID_LEN + 10)).unwrap();
+        assert_eq!(long.len(), MAX_MSGID_LEN);
+    }
+
+    #[test]
+    fn sanitize_msgid_is_none_for_empty_or_all_invalid_input() {
+        assert_eq!(sanitize_msgid(""), None);
+        assert_eq!(sanitize_msgid(" \t\n"), None);
     }
 }
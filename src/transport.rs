@@ -0,0 +1,389 @@
+//! Ready-made [`Write`] implementations for shipping RFC5424 messages to a
+//! remote syslog collector.
+//!
+//! `Rfc5424Writer` only needs a generic [`Write`], so the framing and
+//! connection management required once a message leaves the process is
+//! left to the caller. The transports here fill that gap:
+//!
+//! * [`TcpTransport`] frames each message per RFC6587, either with
+//!   octet-counting (`MSG-LEN SP SYSLOG-MSG`) or non-transparent framing
+//!   (a trailing `\n`).
+//! * [`UdpTransport`] sends one datagram per record, per RFC5426.
+//! * [`UnixTransport`] writes to a local datagram socket such as
+//!   `/dev/log`.
+//!
+//! Every transport here owns its socket and reconnects with capped
+//! exponential backoff when a write fails, so a collector restart (or a
+//! momentarily unreachable syslog daemon) doesn't permanently break the
+//! drain: the next record just pays the cost of one more reconnect
+//! attempt.
+//!
+//! Each [`write`](Write::write_all) call is expected to carry exactly one
+//! already-formatted RFC5424 message, which is how [`Rfc5424Writer`](crate::Rfc5424Writer)
+//! uses its writer.
+
+use std::io::{self, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+/// How an RFC5424 message is delimited when sent over a stream transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// RFC6587 octet-counting: `MSG-LEN SP SYSLOG-MSG`.
+    OctetCounting,
+    /// Non-transparent framing: the message followed by a trailing `\n`.
+    NonTransparent,
+}
+
+/// Capped exponential backoff schedule used by transports when
+/// reconnecting after a write error.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    /// Create a backoff schedule starting at `initial` and doubling on
+    /// every consecutive failure, capped at `max`.
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff { initial, max }
+    }
+
+    fn delay(&self, failures: u32) -> Duration {
+        let exp = failures.saturating_sub(1).min(20);
+        let factor = 1u32.checked_shl(exp).unwrap_or(u32::MAX);
+        self.initial.checked_mul(factor).unwrap_or(self.max).min(self.max)
+    }
+}
+
+impl Default for Backoff {
+    /// Starts at 100ms, doubles on every failure, capped at 30s.
+    fn default() -> Backoff {
+        Backoff::new(Duration::from_millis(100), Duration::from_secs(30))
+    }
+}
+
+/// Render the RFC6587 octet-counting prefix (`MSG-LEN SP`) for a message
+/// of `len` bytes.
+fn octet_count_prefix(len: usize) -> String {
+    format!("{} ", len)
+}
+
+/// Replace any embedded `\n`/`\r` with a space, as RFC6587 requires for
+/// non-transparent framing so an embedded line ending can't be mistaken
+/// for the trailing delimiter.
+fn replace_embedded_newlines(buf: &[u8]) -> Vec<u8> {
+    buf.iter().map(|&b| if b == b'\n' || b == b'\r' { b' ' } else { b }).collect()
+}
+
+/// A TCP transport that frames each message per RFC6587 and transparently
+/// reconnects if the connection to the collector is lost.
+#[derive(Debug)]
+pub struct TcpTransport {
+    addr: String,
+    framing: Framing,
+    backoff: Backoff,
+    stream: Option<TcpStream>,
+    failures: u32,
+}
+
+impl TcpTransport {
+    /// Connect to `addr` (e.g. `"collector:6514"`) using the given
+    /// framing and the default backoff schedule.
+    pub fn connect<A: Into<String>>(addr: A, framing: Framing) -> io::Result<TcpTransport> {
+        TcpTransport::connect_with_backoff(addr, framing, Backoff::default())
+    }
+
+    /// Like [`connect`](TcpTransport::connect), but with a custom backoff
+    /// schedule for reconnect attempts.
+    pub fn connect_with_backoff<A: Into<String>>(
+        addr: A,
+        framing: Framing,
+        backoff: Backoff,
+    ) -> io::Result<TcpTransport> {
+        let addr = addr.into();
+        let stream = TcpStream::connect(&addr)?;
+        Ok(TcpTransport {
+            addr,
+            framing,
+            backoff,
+            stream: Some(stream),
+            failures: 0,
+        })
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        if self.failures > 0 {
+            thread::sleep(self.backoff.delay(self.failures));
+        }
+        match TcpStream::connect(&self.addr) {
+            Ok(stream) => {
+                self.stream = Some(stream);
+                self.failures = 0;
+                Ok(())
+            }
+            Err(err) => {
+                self.failures = self.failures.saturating_add(1);
+                Err(err)
+            }
+        }
+    }
+
+    fn send(&mut self, frame: impl Fn(&mut TcpStream) -> io::Result<()>) -> io::Result<()> {
+        if self.stream.is_none() {
+            self.reconnect()?;
+        }
+        let first_attempt = frame(self.stream.as_mut().expect("checked above"));
+        if first_attempt.is_ok() {
+            return first_attempt;
+        }
+
+        self.stream = None;
+        self.reconnect()?;
+        frame(self.stream.as_mut().expect("just reconnected"))
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self.framing {
+            Framing::OctetCounting => {
+                let prefix = octet_count_prefix(buf.len());
+                self.send(|stream| {
+                    stream.write_all(prefix.as_bytes())?;
+                    stream.write_all(buf)
+                })
+            }
+            Framing::NonTransparent => {
+                // RFC6587 warns that an embedded LF (or CR) would be
+                // mistaken for the trailing delimiter by the receiver,
+                // desyncing the rest of the connection's framing.
+                let sanitized = replace_embedded_newlines(buf);
+                self.send(|stream| {
+                    stream.write_all(&sanitized)?;
+                    stream.write_all(b"\n")
+                })
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stream.as_mut() {
+            Some(stream) => stream.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A UDP transport that sends one datagram per record, per RFC5426.
+#[derive(Debug)]
+pub struct UdpTransport {
+    addr: String,
+    backoff: Backoff,
+    socket: Option<UdpSocket>,
+    failures: u32,
+}
+
+impl UdpTransport {
+    /// Bind an ephemeral local socket and target it at `addr` (e.g.
+    /// `"collector:514"`) using the default backoff schedule.
+    pub fn connect<A: Into<String>>(addr: A) -> io::Result<UdpTransport> {
+        UdpTransport::connect_with_backoff(addr, Backoff::default())
+    }
+
+    /// Like [`connect`](UdpTransport::connect), but with a custom backoff
+    /// schedule for reconnect attempts.
+    pub fn connect_with_backoff<A: Into<String>>(addr: A, backoff: Backoff) -> io::Result<UdpTransport> {
+        let addr = addr.into();
+        let socket = UdpTransport::bind(&addr)?;
+        Ok(UdpTransport {
+            addr,
+            backoff,
+            socket: Some(socket),
+            failures: 0,
+        })
+    }
+
+    fn bind(addr: &str) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        Ok(socket)
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        if self.failures > 0 {
+            thread::sleep(self.backoff.delay(self.failures));
+        }
+        match UdpTransport::bind(&self.addr) {
+            Ok(socket) => {
+                self.socket = Some(socket);
+                self.failures = 0;
+                Ok(())
+            }
+            Err(err) => {
+                self.failures = self.failures.saturating_add(1);
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Write for UdpTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.socket.is_none() {
+            self.reconnect()?;
+        }
+        if self.socket.as_ref().expect("checked above").send(buf).is_ok() {
+            return Ok(());
+        }
+
+        self.socket = None;
+        self.reconnect()?;
+        self.socket
+            .as_ref()
+            .expect("just reconnected")
+            .send(buf)
+            .map(|_| ())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A transport writing to a local datagram socket such as `/dev/log`.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixTransport {
+    path: PathBuf,
+    backoff: Backoff,
+    socket: Option<UnixDatagram>,
+    failures: u32,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    /// Connect to the local syslog socket at `/dev/log`, using the
+    /// default backoff schedule.
+    pub fn connect_dev_log() -> io::Result<UnixTransport> {
+        UnixTransport::connect("/dev/log")
+    }
+
+    /// Connect to the local datagram socket at `path`, using the default
+    /// backoff schedule.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<UnixTransport> {
+        UnixTransport::connect_with_backoff(path, Backoff::default())
+    }
+
+    /// Like [`connect`](UnixTransport::connect), but with a custom
+    /// backoff schedule for reconnect attempts.
+    pub fn connect_with_backoff<P: AsRef<Path>>(path: P, backoff: Backoff) -> io::Result<UnixTransport> {
+        let path = path.as_ref().to_path_buf();
+        let socket = UnixTransport::bind(&path)?;
+        Ok(UnixTransport {
+            path,
+            backoff,
+            socket: Some(socket),
+            failures: 0,
+        })
+    }
+
+    fn bind(path: &Path) -> io::Result<UnixDatagram> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(socket)
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        if self.failures > 0 {
+            thread::sleep(self.backoff.delay(self.failures));
+        }
+        match UnixTransport::bind(&self.path) {
+            Ok(socket) => {
+                self.socket = Some(socket);
+                self.failures = 0;
+                Ok(())
+            }
+            Err(err) => {
+                self.failures = self.failures.saturating_add(1);
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Write for UnixTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.socket.is_none() {
+            self.reconnect()?;
+        }
+        if self.socket.as_ref().expect("checked above").send(buf).is_ok() {
+            return Ok(());
+        }
+
+        self.socket = None;
+        self.reconnect()?;
+        self.socket
+            .as_ref()
+            .expect("just reconnected")
+            .send(buf)
+            .map(|_| ())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_delay_doubles_until_capped() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(200));
+        assert_eq!(backoff.delay(3), Duration::from_millis(400));
+        assert_eq!(backoff.delay(4), Duration::from_millis(800));
+        assert_eq!(backoff.delay(5), Duration::from_secs(1));
+        assert_eq!(backoff.delay(100), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn octet_count_prefix_is_decimal_length_then_space() {
+        assert_eq!(octet_count_prefix(0), "0 ");
+        assert_eq!(octet_count_prefix(42), "42 ");
+        assert_eq!(octet_count_prefix(1024), "1024 ");
+    }
+
+    #[test]
+    fn replace_embedded_newlines_swaps_lf_and_cr_for_spaces() {
+        let sanitized = replace_embedded_newlines(b"multi\nline\r\nmessage");
+        assert_eq!(sanitized, b"multi line  message");
+    }
+}